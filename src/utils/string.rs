@@ -1,5 +1,6 @@
 use itertools::Itertools;
 use regex::Regex;
+use std::collections::HashMap;
 use std::ops::Bound::{Excluded, Included, Unbounded};
 use std::ops::RangeBounds;
 
@@ -18,9 +19,101 @@ pub fn take_lines<R: RangeBounds<usize>>(s: &str, range: R) -> String {
     }
 }
 
+/// Take a range of bytes from a string (e.g. for `{{#include file.rs:bytes:A:B}}`).
+/// Bounds are clamped like [`take_lines`]' end bound, then snapped independently and
+/// inward (the start forward, the end backward) to a char boundary, so a range can't
+/// panic by landing inside a multi-byte char.
+pub fn take_bytes<R: RangeBounds<usize>>(s: &str, range: R) -> &str {
+    let start = match range.start_bound() {
+        Excluded(&n) => n + 1,
+        Included(&n) => n,
+        Unbounded => 0,
+    }
+    .min(s.len());
+    let end = match range.end_bound() {
+        Excluded(&n) => n,
+        Included(&n) => n + 1,
+        Unbounded => s.len(),
+    }
+    .min(s.len());
+
+    if start >= end {
+        return "";
+    }
+
+    // Snap independently (not relative to each other), so a range with no
+    // boundary in between comes out empty below instead of panicking.
+    let start = (start..=s.len()).find(|&i| s.is_char_boundary(i)).unwrap();
+    let end = (0..=end).rev().find(|&i| s.is_char_boundary(i)).unwrap();
+
+    if start >= end {
+        return "";
+    }
+
+    &s[start..end]
+}
+
 /// Take anchored lines from a string.
 /// Lines containing anchor are ignored.
 pub fn take_anchored_lines(s: &str, anchor: &str) -> String {
+    take_anchored_lines_checked(s, anchor).0
+}
+
+/// Take anchored lines from a string, like [`take_anchored_lines`], plus the
+/// zero-based indices of the retained lines marked for highlighting with
+/// `HIGHLIGHT:`/`HIGHLIGHT_END:` (or a single `HL` line for just the next line).
+pub fn take_anchored_lines_with_highlights(s: &str, anchor: &str) -> (String, Vec<usize>) {
+    lazy_static! {
+        static ref RE_START: Regex = Regex::new(r"ANCHOR:\s*(?P<anchor_name>[\w_-]+)").unwrap();
+        static ref RE_END: Regex = Regex::new(r"ANCHOR_END:\s*(?P<anchor_name>[\w_-]+)").unwrap();
+        static ref RE_HL_START: Regex = Regex::new(r"^\s*HIGHLIGHT:\s*$").unwrap();
+        static ref RE_HL_END: Regex = Regex::new(r"^\s*HIGHLIGHT_END:\s*$").unwrap();
+        static ref RE_HL_LINE: Regex = Regex::new(r"^\s*HL\s*$").unwrap();
+    }
+
+    let mut retained = Vec::<&str>::new();
+    let mut highlighted = Vec::<usize>::new();
+    let mut anchor_found = false;
+    let mut highlight_depth = 0usize;
+    let mut highlight_next = false;
+
+    for l in s.lines() {
+        if anchor_found {
+            match RE_END.captures(l) {
+                Some(cap) => {
+                    if &cap["anchor_name"] == anchor {
+                        break;
+                    }
+                }
+                None => {
+                    if RE_HL_START.is_match(l) {
+                        highlight_depth += 1;
+                    } else if RE_HL_END.is_match(l) {
+                        highlight_depth = highlight_depth.saturating_sub(1);
+                    } else if RE_HL_LINE.is_match(l) {
+                        highlight_next = true;
+                    } else if !RE_START.is_match(l) {
+                        if highlight_depth > 0 || highlight_next {
+                            highlighted.push(retained.len());
+                        }
+                        highlight_next = false;
+                        retained.push(l);
+                    }
+                }
+            }
+        } else if let Some(cap) = RE_START.captures(l) {
+            if &cap["anchor_name"] == anchor {
+                anchor_found = true;
+            }
+        }
+    }
+
+    (retained.join("\n"), highlighted)
+}
+
+/// Take a single anchor, like [`take_anchored_lines`], but also report
+/// whether the anchor was found at all.
+fn take_anchored_lines_checked(s: &str, anchor: &str) -> (String, bool) {
     lazy_static! {
         static ref RE_START: Regex = Regex::new(r"ANCHOR:\s*(?P<anchor_name>[\w_-]+)").unwrap();
         static ref RE_END: Regex = Regex::new(r"ANCHOR_END:\s*(?P<anchor_name>[\w_-]+)").unwrap();
@@ -28,6 +121,7 @@ pub fn take_anchored_lines(s: &str, anchor: &str) -> String {
 
     let mut retained = Vec::<&str>::new();
     let mut anchor_found = false;
+    let mut ever_found = false;
 
     for l in s.lines() {
         if anchor_found {
@@ -43,21 +137,203 @@ pub fn take_anchored_lines(s: &str, anchor: &str) -> String {
                     }
                 }
             }
+        } else if let Some(cap) = RE_START.captures(l) {
+            if &cap["anchor_name"] == anchor {
+                anchor_found = true;
+                ever_found = true;
+            }
+        }
+    }
+
+    (retained.join("\n"), ever_found)
+}
+
+/// Take and concatenate several named anchors from a string, in order, joined by
+/// `separator`. A missing anchor contributes an empty fragment and logs a warning.
+pub fn take_anchored_lines_many(s: &str, anchors: &[&str], separator: &str) -> String {
+    anchors
+        .iter()
+        .map(|anchor| {
+            let (text, found) = take_anchored_lines_checked(s, anchor);
+            if !found {
+                log::warn!("Anchor '{}' was not found", anchor);
+            }
+            text
+        })
+        .join(separator)
+}
+
+/// A single line of a diff between two texts, as produced by [`diff_lines`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine<'a> {
+    /// The line is present, unchanged, in both texts.
+    Same(&'a str),
+    /// The line was present in the old text but is gone from the new one.
+    Removed(&'a str),
+    /// The line is present in the new text but wasn't in the old one.
+    Added(&'a str),
+}
+
+/// Diff two texts line-by-line, aligning them with a classic LCS.
+///
+/// The common prefix/suffix is trimmed before building the `n·m` LCS table, so its
+/// size tracks the differing region rather than the full (otherwise-quadratic) input.
+pub fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+
+    let prefix = old_lines
+        .iter()
+        .zip(new_lines.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old_lines[prefix..]
+        .iter()
+        .rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let old_mid = &old_lines[prefix..old_lines.len() - suffix];
+    let new_mid = &new_lines[prefix..new_lines.len() - suffix];
+    let (n, m) = (old_mid.len(), new_mid.len());
+
+    let mut lcs_len = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs_len[i][j] = if old_mid[i] == new_mid[j] {
+                lcs_len[i + 1][j + 1] + 1
+            } else {
+                lcs_len[i + 1][j].max(lcs_len[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::with_capacity(prefix + n + m + suffix);
+    diff.extend(old_lines[..prefix].iter().copied().map(DiffLine::Same));
+
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old_mid[i] == new_mid[j] {
+            diff.push(DiffLine::Same(old_mid[i]));
+            i += 1;
+            j += 1;
+        } else if lcs_len[i + 1][j] >= lcs_len[i][j + 1] {
+            diff.push(DiffLine::Removed(old_mid[i]));
+            i += 1;
         } else {
-            if let Some(cap) = RE_START.captures(l) {
-                if &cap["anchor_name"] == anchor {
-                    anchor_found = true;
-                }
+            diff.push(DiffLine::Added(new_mid[j]));
+            j += 1;
+        }
+    }
+    diff.extend(old_mid[i..n].iter().copied().map(DiffLine::Removed));
+    diff.extend(new_mid[j..m].iter().copied().map(DiffLine::Added));
+
+    diff.extend(
+        old_lines[old_lines.len() - suffix..]
+            .iter()
+            .copied()
+            .map(DiffLine::Same),
+    );
+
+    diff
+}
+
+/// Render the diff between `old` and `new` as a unified-diff-like report
+/// (a leading `-`/`+`/` ` per line), or `None` if they're identical.
+pub fn format_diff(old: &str, new: &str) -> Option<String> {
+    let diff = diff_lines(old, new);
+    if diff.iter().all(|line| matches!(line, DiffLine::Same(_))) {
+        return None;
+    }
+
+    Some(
+        diff.into_iter()
+            .map(|line| match line {
+                DiffLine::Same(l) => format!("  {}", l),
+                DiffLine::Removed(l) => format!("- {}", l),
+                DiffLine::Added(l) => format!("+ {}", l),
+            })
+            .join("\n"),
+    )
+}
+
+/// Whether [`IncludeSnapshotCache::check`] should only report a stale snapshot, or
+/// turn it into an error (the `warn`/`strict` modes a `book.toml` switch picks between).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StalenessMode {
+    /// Report drift but let the build continue.
+    Warn,
+    /// Treat drift as a build failure.
+    Strict,
+}
+
+/// The result of checking a freshly resolved include against its previously
+/// recorded snapshot, via [`IncludeSnapshotCache::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Staleness {
+    /// No snapshot was recorded yet for this key; `text` has been recorded
+    /// as the new baseline.
+    Recorded,
+    /// The freshly extracted text matches the recorded snapshot.
+    Unchanged,
+    /// The text drifted from the recorded snapshot; `diff` is the
+    /// human-readable report from [`format_diff`].
+    Stale { diff: String },
+}
+
+/// Caches the text previously extracted for an include, keyed by whatever the caller
+/// uses to identify it (e.g. `{file}:{range-or-anchor}`), to detect drift on rebuilds.
+#[derive(Debug, Default)]
+pub struct IncludeSnapshotCache {
+    snapshots: HashMap<String, String>,
+}
+
+impl IncludeSnapshotCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compare `text` against the snapshot recorded for `key`, recording it as the new
+    /// baseline if none existed yet. Under [`StalenessMode::Strict`], drift is returned
+    /// as `Err(diff)` instead of `Ok(Staleness::Stale { .. })`.
+    pub fn check(
+        &mut self,
+        key: &str,
+        text: &str,
+        mode: StalenessMode,
+    ) -> Result<Staleness, String> {
+        let staleness = match self.snapshots.get(key) {
+            None => {
+                self.snapshots.insert(key.to_string(), text.to_string());
+                Staleness::Recorded
             }
+            Some(old) => match format_diff(old, text) {
+                Some(diff) => Staleness::Stale { diff },
+                None => Staleness::Unchanged,
+            },
+        };
+
+        match (staleness, mode) {
+            (Staleness::Stale { diff }, StalenessMode::Strict) => Err(diff),
+            (staleness, _) => Ok(staleness),
         }
     }
 
-    retained.join("\n")
+    /// Accept `text` as the new recorded snapshot for `key`, e.g. after a
+    /// reported drift has been reviewed.
+    pub fn accept(&mut self, key: &str, text: &str) {
+        self.snapshots.insert(key.to_string(), text.to_string());
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{take_anchored_lines, take_lines};
+    use super::{
+        diff_lines, format_diff, take_anchored_lines, take_anchored_lines_many,
+        take_anchored_lines_with_highlights, take_bytes, take_lines, DiffLine,
+        IncludeSnapshotCache, Staleness, StalenessMode,
+    };
 
     #[test]
     fn take_lines_test() {
@@ -71,6 +347,30 @@ mod tests {
         assert_eq!(take_lines(s, ..100), s);
     }
 
+    #[test]
+    fn take_bytes_test() {
+        let s = "Lorem ipsum dolor sit amet";
+        assert_eq!(take_bytes(s, 6..11), "ipsum");
+        assert_eq!(take_bytes(s, 18..), "sit amet");
+        assert_eq!(take_bytes(s, ..5), "Lorem");
+        assert_eq!(take_bytes(s, ..), s);
+        // corner cases
+        assert_eq!(take_bytes(s, 11..6), "");
+        assert_eq!(take_bytes(s, ..1000), s);
+
+        // snaps inward to the nearest char boundary instead of panicking
+        let s = "a→b";
+        assert_eq!(s.len(), 5);
+        assert_eq!(take_bytes(s, 0..2), "a");
+        assert_eq!(take_bytes(s, 2..5), "b");
+        assert_eq!(take_bytes(s, 1..4), "→");
+
+        // a range with no boundary between its ends (i.e. entirely inside a
+        // multi-byte character) has nothing valid to return, but must not panic
+        assert_eq!(take_bytes(s, 2..3), "");
+        assert_eq!(take_bytes(s, 3..4), "");
+    }
+
     #[test]
     fn take_anchored_lines_test() {
         let s = "Lorem\nipsum\ndolor\nsit\namet";
@@ -99,4 +399,152 @@ mod tests {
         assert_eq!(take_anchored_lines(s, "test"), "dolor\nsit\namet");
         assert_eq!(take_anchored_lines(s, "something"), "");
     }
+
+    #[test]
+    fn take_anchored_lines_with_highlights_test() {
+        let s = "Lorem\nANCHOR: test\nipsum\nHIGHLIGHT:\ndolor\nsit\nHIGHLIGHT_END:\namet\nANCHOR_END: test";
+        assert_eq!(
+            take_anchored_lines_with_highlights(s, "test"),
+            ("ipsum\ndolor\nsit\namet".to_string(), vec![1, 2])
+        );
+
+        // a single `HL` line highlights only the line right after it
+        let s = "ANCHOR: test\nipsum\nHL\ndolor\nsit\nANCHOR_END: test";
+        assert_eq!(
+            take_anchored_lines_with_highlights(s, "test"),
+            ("ipsum\ndolor\nsit".to_string(), vec![1])
+        );
+
+        // nested highlight spans don't shift the reported indices
+        let s = "ANCHOR: test\nipsum\nHIGHLIGHT:\ndolor\nHIGHLIGHT:\nsit\nHIGHLIGHT_END:\namet\nHIGHLIGHT_END:\nlorem\nANCHOR_END: test";
+        assert_eq!(
+            take_anchored_lines_with_highlights(s, "test"),
+            ("ipsum\ndolor\nsit\namet\nlorem".to_string(), vec![1, 2, 3])
+        );
+
+        assert_eq!(
+            take_anchored_lines_with_highlights(s, "something"),
+            (String::new(), vec![])
+        );
+    }
+
+    #[test]
+    fn take_anchored_lines_many_test() {
+        let s = "use std::io;\nANCHOR: imports\nuse std::fs;\nANCHOR_END: imports\nfn helper() {}\nANCHOR: main\nfn main() {}\nANCHOR_END: main";
+        assert_eq!(
+            take_anchored_lines_many(s, &["imports", "main"], "\n\n"),
+            "use std::fs;\n\nfn main() {}"
+        );
+
+        // order is respected
+        assert_eq!(
+            take_anchored_lines_many(s, &["main", "imports"], "\n\n"),
+            "fn main() {}\n\nuse std::fs;"
+        );
+
+        // a missing anchor contributes an empty fragment, not an error
+        assert_eq!(
+            take_anchored_lines_many(s, &["imports", "something"], "\n\n"),
+            "use std::fs;\n\n"
+        );
+    }
+
+    #[test]
+    fn diff_lines_test() {
+        let old = "Lorem\nipsum\ndolor";
+        assert_eq!(
+            diff_lines(old, old),
+            vec![
+                DiffLine::Same("Lorem"),
+                DiffLine::Same("ipsum"),
+                DiffLine::Same("dolor"),
+            ]
+        );
+
+        let new = "Lorem\nipsum dolor\ndolor";
+        assert_eq!(
+            diff_lines(old, new),
+            vec![
+                DiffLine::Same("Lorem"),
+                DiffLine::Removed("ipsum"),
+                DiffLine::Added("ipsum dolor"),
+                DiffLine::Same("dolor"),
+            ]
+        );
+
+        let new = "Lorem\nipsum\ndolor\nsit";
+        assert_eq!(
+            diff_lines(old, new),
+            vec![
+                DiffLine::Same("Lorem"),
+                DiffLine::Same("ipsum"),
+                DiffLine::Same("dolor"),
+                DiffLine::Added("sit"),
+            ]
+        );
+    }
+
+    #[test]
+    fn format_diff_test() {
+        let old = "Lorem\nipsum\ndolor";
+        assert_eq!(format_diff(old, old), None);
+
+        let new = "Lorem\nipsum\nsit";
+        assert_eq!(
+            format_diff(old, new),
+            Some("  Lorem\n  ipsum\n- dolor\n+ sit".to_string())
+        );
+    }
+
+    #[test]
+    fn include_snapshot_cache_test() {
+        let mut cache = IncludeSnapshotCache::new();
+
+        // first sighting of a key just records a baseline
+        assert_eq!(
+            cache.check("src/main.rs:imports", "use std::fs;", StalenessMode::Warn),
+            Ok(Staleness::Recorded)
+        );
+
+        // unchanged on the next check
+        assert_eq!(
+            cache.check("src/main.rs:imports", "use std::fs;", StalenessMode::Warn),
+            Ok(Staleness::Unchanged)
+        );
+
+        // warn mode reports a changed source as a diff instead of failing silently
+        assert_eq!(
+            cache.check(
+                "src/main.rs:imports",
+                "use std::fs;\nuse std::io;",
+                StalenessMode::Warn
+            ),
+            Ok(Staleness::Stale {
+                diff: "  use std::fs;\n+ use std::io;".to_string()
+            })
+        );
+
+        // accepting the drift makes it the new baseline
+        cache.accept("src/main.rs:imports", "use std::fs;\nuse std::io;");
+        assert_eq!(
+            cache.check(
+                "src/main.rs:imports",
+                "use std::fs;\nuse std::io;",
+                StalenessMode::Warn
+            ),
+            Ok(Staleness::Unchanged)
+        );
+
+        // strict mode turns drift into an error instead of a reportable value
+        assert_eq!(
+            cache.check("src/main.rs:imports", "use std::fs;", StalenessMode::Strict),
+            Err("  use std::fs;\n- use std::io;".to_string())
+        );
+
+        // a different key is tracked independently
+        assert_eq!(
+            cache.check("src/main.rs:main", "fn main() {}", StalenessMode::Strict),
+            Ok(Staleness::Recorded)
+        );
+    }
 }